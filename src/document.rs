@@ -1,17 +1,16 @@
 //! Provides the `Document` struct and related functionality for handling text documents in the LSP server.
 
+use crate::line_index::{LineIndex, PositionEncoding};
 use log::debug;
 use lsp_types::{
-    Location, Position, Range, SymbolInformation, SymbolKind, TextDocumentContentChangeEvent, Url,
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, Location, Position, Range, SelectionRange,
+    SymbolInformation, SymbolKind, TextDocumentContentChangeEvent, Url,
 };
 use ropey::Rope;
-use ruff_python_ast::{
-    Expr, ExprName, ModModule, Stmt, StmtAnnAssign, StmtAssign, StmtClassDef, StmtFor,
-    StmtFunctionDef, StmtImport, StmtImportFrom,
-};
-use ruff_python_parser::parse_program;
-use ruff_text_size::TextRange;
-use std::collections::HashMap;
+use ruff_python_ast::visitor::{self, Visitor};
+use ruff_python_ast::{Expr, ModModule, Ranged, Stmt};
+use ruff_python_parser::{parse_program, ParseError};
+use ruff_text_size::{TextRange, TextSize};
 use thiserror::Error;
 
 /// Represents a text document and its associated data.
@@ -20,14 +19,21 @@ use thiserror::Error;
 /// - The URI of the document.
 /// - The content of the document as a `Rope` data structure.
 /// - The parsed AST of the document, if available.
-/// - A mapping of character offsets to line numbers.
+/// - An offset index translating between LSP positions and `char` offsets.
 /// - The symbol table of the document.
+/// - The position encoding negotiated with the client.
+/// - The diagnostics produced by the last parse.
+/// - A monotonically increasing version bumped on every edit, used to discard
+///   results computed against a stale document.
 pub struct Document {
     pub uri: Url,
     pub content: Rope,
     pub ast: Option<ModModule>,
-    pub line_number_map: Vec<usize>,
+    pub line_index: LineIndex,
     pub symbol_table: SymbolTable,
+    pub encoding: PositionEncoding,
+    pub diagnostics: Vec<Diagnostic>,
+    pub version: i64,
 }
 
 #[derive(Debug, Error)]
@@ -37,265 +43,446 @@ pub enum DocumentError {
 }
 
 impl Document {
-    /// Creates a new `Document` instance with the given URI and content.
-    pub fn new(uri: Url, content: String) -> Self {
+    /// Creates a new `Document` instance with the given URI, content and encoding.
+    pub fn new(uri: Url, content: String, encoding: PositionEncoding) -> Self {
         let rope = Rope::from_str(&content);
-        let line_number_map = Self::compute_line_number_map(&content);
-        let ast = parse_rope_to_ast(&rope).ok();
+        let line_index = LineIndex::new(&rope);
+        let (ast, errors) = parse_rope(&rope);
         debug!("Parsed AST: {:#?}", ast);
 
         let mut document = Document {
             uri,
             content: rope,
             ast,
-            line_number_map,
+            line_index,
             symbol_table: SymbolTable::new(),
+            encoding,
+            diagnostics: Vec::new(),
+            version: 0,
         };
 
         document.symbol_table = document.compute_symbol_table();
+        document.diagnostics = document.parse_diagnostics(&errors);
 
         document
     }
 
-    /// Updates the document with the given changes and recomputes the AST and symbol table.
-    pub fn update(
+    /// Applies the given changes to the content and bumps the version.
+    ///
+    /// This only touches the `Rope` and the offset index; the comparatively
+    /// expensive reparse is deferred to [`Document::reparse`] so that bursts of
+    /// edits can be coalesced by the background worker.
+    pub fn apply_edit(
         &mut self,
         changes: &[TextDocumentContentChangeEvent],
     ) -> Result<(), DocumentError> {
-        apply_changes(&mut self.content, changes)?;
-        self.line_number_map = Self::compute_line_number_map(&self.content.to_string());
-        self.ast = parse_rope_to_ast(&self.content).ok();
+        apply_changes(&mut self.content, changes, self.encoding)?;
+        self.line_index = LineIndex::new(&self.content);
+        self.version += 1;
+        Ok(())
+    }
+
+    /// Reparses the current content, refreshing the AST, symbol table and
+    /// diagnostics.
+    pub fn reparse(&mut self) {
+        let (ast, errors) = parse_rope(&self.content);
+        self.ast = ast;
         self.symbol_table = self.compute_symbol_table();
+        self.diagnostics = self.parse_diagnostics(&errors);
+    }
+
+    /// Applies changes and reparses immediately, bumping the version.
+    pub fn update(
+        &mut self,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> Result<(), DocumentError> {
+        self.apply_edit(changes)?;
+        self.reparse();
         Ok(())
     }
 
-    /// Computes the line number mapping for the given document content.
-    pub fn compute_line_number_map(content: &str) -> Vec<usize> {
-        let mut line_number_map = Vec::new();
-        let mut char_count = 0;
+    /// Maps parser errors into LSP [`Diagnostic`]s in the negotiated encoding.
+    fn parse_diagnostics(&self, errors: &[ParseError]) -> Vec<Diagnostic> {
+        errors
+            .iter()
+            .map(|error| Diagnostic {
+                range: self.range_of(&error.location),
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("fluxion-lsp".to_string()),
+                message: error.error.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
 
-        for line in content.lines() {
-            line_number_map.push(char_count);
-            char_count += line.len() + 1; // +1 for the newline character
-        }
+    /// Converts an LSP [`Position`] into a flat `char` offset, honoring the
+    /// document's negotiated position encoding.
+    pub fn offset_at(&self, position: Position) -> usize {
+        self.line_index.position_to_char(position, self.encoding)
+    }
 
-        line_number_map
+    /// Converts a flat `char` offset into an LSP [`Position`] in the document's
+    /// negotiated encoding.
+    pub fn position_at(&self, offset: usize) -> Position {
+        self.line_index.char_to_position(offset, self.encoding)
     }
 
-    /// Retrieves the line number for the given character offset in the document.
-    pub fn get_line_number(&self, offset: usize) -> Option<u32> {
-        self.line_number_map.binary_search(&offset).map_or_else(
-            |index| index.checked_sub(1).map(|i| i as u32),
-            |index| Some(index as u32),
-        )
+    /// Returns the identifier of the innermost `ExprName` whose span covers the
+    /// given position, if any.
+    pub fn name_at(&self, position: Position) -> Option<String> {
+        let ast = self.ast.as_ref()?;
+        let byte_offset = self.content.char_to_byte(self.offset_at(position));
+        let target = TextSize::try_from(byte_offset).ok()?;
+
+        let mut finder = NameFinder {
+            target,
+            found: None,
+        };
+        for stmt in &ast.body {
+            finder.visit_stmt(stmt);
+        }
+        finder.found.map(|(name, _)| name)
     }
 
-    /// Computes the symbol table for the document based on its AST.
-    fn compute_symbol_table(&self) -> SymbolTable {
-        let mut symbol_table = SymbolTable::new();
+    /// Builds the chain of progressively larger syntactic ranges enclosing the
+    /// given position, from the innermost node out to the whole module.
+    pub fn selection_range(&self, position: Position) -> Option<SelectionRange> {
+        let ast = self.ast.as_ref()?;
+        let byte_offset = self.content.char_to_byte(self.offset_at(position));
+        let target = TextSize::try_from(byte_offset).ok()?;
 
-        if let Some(ast) = &self.ast {
-            for stmt in &ast.body {
-                if let Some(symbol_info) = self.create_symbol_info(stmt) {
-                    symbol_table.insert(symbol_info);
-                }
-            }
+        let mut collector = RangeCollector {
+            target,
+            ranges: Vec::new(),
+        };
+        for stmt in &ast.body {
+            collector.visit_stmt(stmt);
         }
+        // Always anchor the chain at the enclosing module.
+        let module = TextRange::new(TextSize::from(0), self.content.len_bytes().try_into().ok()?);
+        collector.ranges.push(module);
+
+        collector.ranges.sort_by_key(|range| range.len());
+        collector.ranges.dedup();
+
+        // Wrap from the outermost range inwards so each node points at its parent.
+        let mut parent: Option<Box<SelectionRange>> = None;
+        for range in collector.ranges.iter().rev() {
+            parent = Some(Box::new(SelectionRange {
+                range: self.range_of(range),
+                parent: parent.take(),
+            }));
+        }
+        parent.map(|boxed| *boxed)
+    }
 
-        symbol_table
+    /// Computes the symbol table for the document based on its AST.
+    ///
+    /// The AST is walked recursively so that methods, nested functions and
+    /// other scoped definitions appear under their enclosing symbol, while a
+    /// flat, source-ordered view is kept for linear lookups such as hover.
+    fn compute_symbol_table(&self) -> SymbolTable {
+        let mut flat = Vec::new();
+        let symbols = match &self.ast {
+            Some(ast) => self.build_symbols(&ast.body, None, &mut flat),
+            None => Vec::new(),
+        };
+        SymbolTable { symbols, flat }
     }
 
-    /// Creates a `SymbolInformation` instance for the given AST statement.
+    /// Recursively builds the `DocumentSymbol` tree for a statement body,
+    /// appending each symbol to `flat` with its `container` name in source order.
     #[allow(deprecated)]
-    fn create_symbol_info(&self, stmt: &Stmt) -> Option<SymbolInformation> {
-        match stmt {
-            Stmt::FunctionDef(StmtFunctionDef { name, range, .. }) => Some(SymbolInformation {
-                name: name.to_string(),
-                kind: SymbolKind::FUNCTION,
-                tags: None,
-                deprecated: None,
-                location: self.create_location(range),
-                container_name: None,
-            }),
-            Stmt::ClassDef(StmtClassDef { name, range, .. }) => Some(SymbolInformation {
-                name: name.to_string(),
-                kind: SymbolKind::CLASS,
-                tags: None,
-                deprecated: None,
-                location: self.create_location(range),
-                container_name: None,
-            }),
-            Stmt::Assign(StmtAssign { targets, range, .. }) => {
-                if let Some(Expr::Name(ExprName { id, .. })) = targets.first() {
-                    Some(SymbolInformation {
-                        name: id.to_string(),
-                        kind: SymbolKind::VARIABLE,
-                        tags: None,
-                        deprecated: None,
-                        location: self.create_location(range),
-                        container_name: None,
-                    })
-                } else {
-                    None
-                }
-            }
-            Stmt::AnnAssign(StmtAnnAssign { target, range, .. }) => {
-                if let Expr::Name(ExprName { id, .. }) = target.as_ref() {
-                    Some(SymbolInformation {
-                        name: id.to_string(),
-                        kind: SymbolKind::VARIABLE,
-                        tags: None,
-                        deprecated: None,
-                        location: self.create_location(range),
-                        container_name: None,
-                    })
-                } else {
-                    None
-                }
-            }
-            Stmt::For(StmtFor { target, range, .. }) => {
-                if let Expr::Name(ExprName { id, .. }) = target.as_ref() {
-                    Some(SymbolInformation {
-                        name: id.to_string(),
-                        kind: SymbolKind::VARIABLE,
-                        tags: None,
-                        deprecated: None,
-                        location: self.create_location(range),
-                        container_name: None,
-                    })
-                } else {
-                    None
-                }
-            }
-            Stmt::Import(StmtImport { names, range, .. }) => {
-                names.first().map(|name| SymbolInformation {
-                    name: name.name.to_string(),
-                    kind: SymbolKind::MODULE,
+    fn build_symbols(
+        &self,
+        body: &[Stmt],
+        container: Option<&str>,
+        flat: &mut Vec<SymbolInformation>,
+    ) -> Vec<DocumentSymbol> {
+        let mut symbols = Vec::new();
+
+        for stmt in body {
+            // A single statement may bind several names (e.g. `import a, b` or
+            // `from m import x, y`), so each one contributes its own symbol.
+            for desc in describe_stmt(stmt) {
+                flat.push(SymbolInformation {
+                    name: desc.name.clone(),
+                    kind: desc.kind,
                     tags: None,
                     deprecated: None,
-                    location: self.create_location(range),
-                    container_name: None,
-                })
-            }
-            Stmt::ImportFrom(StmtImportFrom { module, range, .. }) => {
-                let name = module
-                    .as_ref()
-                    .map(|id| id.to_string())
-                    .unwrap_or_else(|| "unknown".to_string());
-                let location = self.create_location(range);
-                Some(SymbolInformation {
-                    name,
-                    kind: SymbolKind::MODULE,
+                    location: self.create_location(&desc.range),
+                    container_name: container.map(str::to_string),
+                });
+
+                // Descend into the bodies of definitions so their members nest
+                // under the symbol we just recorded.
+                let children = match desc.children {
+                    Some(child_body) => self.build_symbols(child_body, Some(&desc.name), flat),
+                    None => Vec::new(),
+                };
+
+                symbols.push(DocumentSymbol {
+                    name: desc.name,
+                    detail: None,
+                    kind: desc.kind,
                     tags: None,
                     deprecated: None,
-                    location,
-                    container_name: None,
-                })
+                    range: self.range_of(&desc.range),
+                    selection_range: self.range_of(&desc.selection_range),
+                    children: (!children.is_empty()).then_some(children),
+                });
             }
-            _ => None,
         }
+
+        symbols
     }
 
     /// Creates a `Location` instance for the given text range in the document.
     fn create_location(&self, range: &TextRange) -> Location {
-        let start_offset = range.start().to_usize();
-        let end_offset = range.end().to_usize();
+        Location {
+            uri: self.uri.clone(),
+            range: self.range_of(range),
+        }
+    }
 
-        let start_line = self
-            .line_number_map
-            .binary_search(&start_offset)
-            .unwrap_or_else(|index| index.saturating_sub(1));
+    /// Converts a ruff byte [`TextRange`] into an LSP [`Range`] in the
+    /// negotiated position encoding.
+    ///
+    /// ruff reports spans as UTF-8 byte offsets, so they are first mapped to
+    /// `char` offsets via the `Rope` and then to positions via the index.
+    ///
+    /// Byte offsets are clamped to the current document length: the AST may
+    /// briefly lag behind the rope while the reparse worker is debouncing, so a
+    /// span from a pre-edit AST can point past the end of a shrunken document.
+    pub fn range_of(&self, range: &TextRange) -> Range {
+        let len = self.content.len_bytes();
+        let start = self.content.byte_to_char(range.start().to_usize().min(len));
+        let end = self.content.byte_to_char(range.end().to_usize().min(len));
+        Range {
+            start: self.position_at(start),
+            end: self.position_at(end),
+        }
+    }
+}
 
-        let end_line = self
-            .line_number_map
-            .binary_search(&end_offset)
-            .unwrap_or_else(|index| index.saturating_sub(1));
+/// A description of a statement that contributes a symbol, produced while
+/// walking the AST. `children` points at the body to descend into for
+/// definitions that introduce a new scope.
+struct StmtSymbol<'a> {
+    name: String,
+    kind: SymbolKind,
+    range: TextRange,
+    selection_range: TextRange,
+    children: Option<&'a [Stmt]>,
+}
 
-        let start_character = start_offset - self.line_number_map[start_line];
-        let end_character = end_offset - self.line_number_map[end_line];
+/// Extracts the symbols described by a statement, if any.
+///
+/// Most statements contribute a single symbol, but an `import`/`from ... import`
+/// binds one name per alias, so each is recorded under its local binding (the
+/// `as` name when present, otherwise the imported name).
+fn describe_stmt(stmt: &Stmt) -> Vec<StmtSymbol<'_>> {
+    match stmt {
+        Stmt::FunctionDef(def) => vec![StmtSymbol {
+            name: def.name.to_string(),
+            kind: SymbolKind::FUNCTION,
+            range: def.range(),
+            selection_range: def.name.range(),
+            children: Some(&def.body),
+        }],
+        Stmt::ClassDef(def) => vec![StmtSymbol {
+            name: def.name.to_string(),
+            kind: SymbolKind::CLASS,
+            range: def.range(),
+            selection_range: def.name.range(),
+            children: Some(&def.body),
+        }],
+        Stmt::Assign(assign) => match assign.targets.first() {
+            Some(Expr::Name(name)) => vec![StmtSymbol {
+                name: name.id.to_string(),
+                kind: SymbolKind::VARIABLE,
+                range: assign.range(),
+                selection_range: name.range(),
+                children: None,
+            }],
+            _ => Vec::new(),
+        },
+        Stmt::AnnAssign(assign) => match assign.target.as_ref() {
+            Expr::Name(name) => vec![StmtSymbol {
+                name: name.id.to_string(),
+                kind: SymbolKind::VARIABLE,
+                range: assign.range(),
+                selection_range: name.range(),
+                children: None,
+            }],
+            _ => Vec::new(),
+        },
+        Stmt::For(for_stmt) => match for_stmt.target.as_ref() {
+            Expr::Name(name) => vec![StmtSymbol {
+                name: name.id.to_string(),
+                kind: SymbolKind::VARIABLE,
+                range: for_stmt.range(),
+                selection_range: name.range(),
+                children: None,
+            }],
+            _ => Vec::new(),
+        },
+        Stmt::Import(import) => import
+            .names
+            .iter()
+            .map(|alias| StmtSymbol {
+                name: alias
+                    .asname
+                    .as_ref()
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| alias.name.to_string()),
+                kind: SymbolKind::MODULE,
+                range: import.range(),
+                selection_range: alias.range(),
+                children: None,
+            })
+            .collect(),
+        Stmt::ImportFrom(import) => import
+            .names
+            .iter()
+            .map(|alias| StmtSymbol {
+                name: alias
+                    .asname
+                    .as_ref()
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| alias.name.to_string()),
+                kind: SymbolKind::MODULE,
+                range: import.range(),
+                selection_range: alias.range(),
+                children: None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
 
-        Location {
-            uri: self.uri.clone(),
-            range: Range {
-                start: Position {
-                    line: start_line as u32,
-                    character: start_character as u32,
-                },
-                end: Position {
-                    line: end_line as u32,
-                    character: end_character as u32,
-                },
-            },
+/// Locates the innermost `ExprName` covering a byte offset while walking the AST.
+struct NameFinder {
+    target: TextSize,
+    found: Option<(String, TextRange)>,
+}
+
+impl<'a> Visitor<'a> for NameFinder {
+    fn visit_expr(&mut self, expr: &'a Expr) {
+        if let Expr::Name(name) = expr {
+            let range = name.range();
+            let covers = range.start() <= self.target && self.target <= range.end();
+            let closer = match &self.found {
+                Some((_, best)) => range.len() <= best.len(),
+                None => true,
+            };
+            if covers && closer {
+                self.found = Some((name.id.to_string(), range));
+            }
         }
+        visitor::walk_expr(self, expr);
     }
 }
 
-/// Represents a symbol table, which maps symbol names to their corresponding `SymbolInformation`.
-#[derive(Debug, Clone)]
+/// Collects the ranges of every AST node covering a byte offset.
+struct RangeCollector {
+    target: TextSize,
+    ranges: Vec<TextRange>,
+}
+
+impl RangeCollector {
+    fn record(&mut self, range: TextRange) {
+        if range.start() <= self.target && self.target <= range.end() {
+            self.ranges.push(range);
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for RangeCollector {
+    fn visit_stmt(&mut self, stmt: &'a Stmt) {
+        self.record(stmt.range());
+        visitor::walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &'a Expr) {
+        self.record(expr.range());
+        visitor::walk_expr(self, expr);
+    }
+}
+
+/// Holds the symbols of a document both as a scope-nested tree and as a flat,
+/// source-ordered list that preserves duplicate names.
+#[derive(Debug, Clone, Default)]
 pub struct SymbolTable {
-    symbols: HashMap<String, SymbolInformation>,
+    /// Top-level symbols, with members nested under their containing symbol.
+    symbols: Vec<DocumentSymbol>,
+    /// Every symbol in source (pre-order) order, duplicates kept.
+    flat: Vec<SymbolInformation>,
 }
 
 #[allow(dead_code)]
 impl SymbolTable {
     /// Creates a new empty `SymbolTable`.
     pub fn new() -> Self {
-        SymbolTable {
-            symbols: HashMap::new(),
-        }
+        SymbolTable::default()
     }
 
-    /// Inserts a `SymbolInformation` into the symbol table.
-    pub fn insert(&mut self, symbol: SymbolInformation) {
-        self.symbols.insert(symbol.name.clone(), symbol);
+    /// Returns the scope-nested document symbols in source order.
+    pub fn document_symbols(&self) -> &[DocumentSymbol] {
+        &self.symbols
     }
 
-    /// Retrieves a `SymbolInformation` from the symbol table by its name.
+    /// Retrieves the first symbol with the given name, in source order.
     pub fn get(&self, name: &str) -> Option<&SymbolInformation> {
-        self.symbols.get(name)
+        self.flat.iter().find(|symbol| symbol.name == name)
     }
 
     /// Checks if the symbol table contains a symbol with the given name.
     pub fn contains(&self, name: &str) -> bool {
-        self.symbols.contains_key(name)
+        self.flat.iter().any(|symbol| symbol.name == name)
     }
 
-    /// Returns an iterator over the `SymbolInformation` values in the symbol table.
+    /// Returns an iterator over the flat `SymbolInformation` list.
     pub fn iter(&self) -> impl Iterator<Item = &SymbolInformation> {
-        self.symbols.values()
+        self.flat.iter()
     }
 
     /// Returns the number of symbols in the symbol table.
     pub fn len(&self) -> usize {
-        self.symbols.len()
+        self.flat.len()
     }
 
     /// Checks if the symbol table is empty.
     pub fn is_empty(&self) -> bool {
-        self.symbols.is_empty()
+        self.flat.is_empty()
     }
 }
 
-/// Converts a `Position` in a text document to the corresponding byte offset in a `Rope`.
-pub fn to_rope_position(document: &Rope, position: Position) -> usize {
-    document.line_to_char(position.line as usize) + position.character as usize
-}
-
-/// Parses the content of a `Rope` into an AST.
-pub fn parse_rope_to_ast(rope: &Rope) -> Result<ModModule, DocumentError> {
+/// Parses the content of a `Rope`, returning either the AST with no errors or,
+/// when parsing fails, no AST and the single error that stopped the parser.
+pub fn parse_rope(rope: &Rope) -> (Option<ModModule>, Vec<ParseError>) {
     let code = rope.to_string();
-    parse_program(&code).map_err(DocumentError::from)
+    match parse_program(&code) {
+        Ok(module) => (Some(module), Vec::new()),
+        Err(error) => (None, vec![error]),
+    }
 }
 
 /// Applies a set of changes to a `Rope` document.
+///
+/// Ranges carried by incremental changes are interpreted in the negotiated
+/// `encoding`; the index is rebuilt per change so offsets stay correct as the
+/// rope is mutated.
 pub fn apply_changes(
     document: &mut Rope,
     changes: &[TextDocumentContentChangeEvent],
+    encoding: PositionEncoding,
 ) -> Result<(), DocumentError> {
     for change in changes {
         if let Some(range) = change.range {
-            let start = to_rope_position(document, range.start);
-            let end = to_rope_position(document, range.end);
+            let index = LineIndex::new(document);
+            let start = index.position_to_char(range.start, encoding);
+            let end = index.position_to_char(range.end, encoding);
             document.remove(start..end);
             document.insert(start, &change.text);
         } else {
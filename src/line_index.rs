@@ -0,0 +1,260 @@
+//! Offset translation between LSP `Position`s and flat character offsets.
+//!
+//! `Position.character` is encoding-dependent: the LSP spec counts UTF-16 code
+//! units by default, but a client may negotiate UTF-8 (byte) or UTF-32
+//! (codepoint) offsets via `positionEncodings`. [`LineIndex`] records, for each
+//! line, its start offset and the location of any non-ASCII characters, so that
+//! conversions between the three encodings and the `char` offsets Ropey works
+//! in stay cheap and internally consistent.
+
+use lsp_types::{Position, PositionEncodingKind};
+use ropey::Rope;
+
+/// The position encoding negotiated with the client during `initialize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    /// UTF-8 code units (bytes).
+    Utf8,
+    /// UTF-16 code units — the LSP default when nothing is negotiated.
+    #[default]
+    Utf16,
+    /// UTF-32 code units (Unicode scalar values, i.e. `char`s).
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Picks the best encoding offered by the client, preferring UTF-16.
+    ///
+    /// Only UTF-8 and UTF-16 are advertised back to the client; UTF-32 is
+    /// supported internally but never negotiated, matching what editors send.
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        let offered = offered.unwrap_or(&[]);
+        if offered.iter().any(|k| *k == PositionEncodingKind::UTF8) {
+            if offered.iter().any(|k| *k == PositionEncodingKind::UTF16) {
+                PositionEncoding::Utf16
+            } else {
+                PositionEncoding::Utf8
+            }
+        } else {
+            PositionEncoding::Utf16
+        }
+    }
+
+    /// The LSP wire representation of this encoding.
+    pub fn to_lsp(self) -> PositionEncodingKind {
+        match self {
+            PositionEncoding::Utf8 => PositionEncodingKind::UTF8,
+            PositionEncoding::Utf16 => PositionEncodingKind::UTF16,
+            PositionEncoding::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+}
+
+/// A character occupying more than one code unit in at least one encoding.
+#[derive(Debug, Clone, Copy)]
+struct WideChar {
+    /// Column of the character, in `char`s relative to the line start.
+    char_col: usize,
+    /// Number of UTF-8 code units (bytes) the character occupies.
+    len_utf8: usize,
+    /// Number of UTF-16 code units the character occupies.
+    len_utf16: usize,
+}
+
+impl WideChar {
+    /// Length of the character in the given encoding, in code units.
+    fn len(&self, enc: PositionEncoding) -> usize {
+        match enc {
+            PositionEncoding::Utf8 => self.len_utf8,
+            PositionEncoding::Utf16 => self.len_utf16,
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+/// A per-document index of line starts and wide-character positions.
+#[derive(Debug, Clone, Default)]
+pub struct LineIndex {
+    /// `char` offset of the first character of each line.
+    line_starts: Vec<usize>,
+    /// Wide characters on each line, in column order.
+    wide_chars: Vec<Vec<WideChar>>,
+    /// Total length of the document, in `char`s.
+    len_chars: usize,
+}
+
+impl LineIndex {
+    /// Builds an index over the current contents of `rope`.
+    pub fn new(rope: &Rope) -> Self {
+        let line_count = rope.len_lines();
+        let mut line_starts = Vec::with_capacity(line_count);
+        let mut wide_chars = Vec::with_capacity(line_count);
+
+        for line_idx in 0..line_count {
+            line_starts.push(rope.line_to_char(line_idx));
+
+            let mut wides = Vec::new();
+            for (col, ch) in rope.line(line_idx).chars().enumerate() {
+                if !ch.is_ascii() {
+                    wides.push(WideChar {
+                        char_col: col,
+                        len_utf8: ch.len_utf8(),
+                        len_utf16: ch.len_utf16(),
+                    });
+                }
+            }
+            wide_chars.push(wides);
+        }
+
+        LineIndex {
+            line_starts,
+            wide_chars,
+            len_chars: rope.len_chars(),
+        }
+    }
+
+    /// Converts an LSP [`Position`] (whose `character` is measured in `enc`
+    /// code units) into a flat `char` offset into the document.
+    pub fn position_to_char(&self, position: Position, enc: PositionEncoding) -> usize {
+        let line = position.line as usize;
+        let Some(&line_start) = self.line_starts.get(line) else {
+            return self.len_chars;
+        };
+        let col = self.enc_to_char_col(line, position.character as usize, enc);
+        (line_start + col).min(self.len_chars)
+    }
+
+    /// Converts a flat `char` offset into the document into an LSP [`Position`]
+    /// whose `character` is measured in `enc` code units.
+    pub fn char_to_position(&self, offset: usize, enc: PositionEncoding) -> Position {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next.saturating_sub(1),
+        };
+        let char_col = offset.saturating_sub(self.line_starts[line]);
+        let character = self.char_col_to_enc(line, char_col, enc);
+        Position {
+            line: line as u32,
+            character: character as u32,
+        }
+    }
+
+    /// Translates a `char` column on `line` into a column in `enc` code units.
+    fn char_col_to_enc(&self, line: usize, char_col: usize, enc: PositionEncoding) -> usize {
+        if enc == PositionEncoding::Utf32 {
+            return char_col;
+        }
+        let mut units = char_col;
+        if let Some(wides) = self.wide_chars.get(line) {
+            for wide in wides {
+                if wide.char_col >= char_col {
+                    break;
+                }
+                units += wide.len(enc) - 1;
+            }
+        }
+        units
+    }
+
+    /// Translates a column in `enc` code units on `line` into a `char` column.
+    fn enc_to_char_col(&self, line: usize, units: usize, enc: PositionEncoding) -> usize {
+        if enc == PositionEncoding::Utf32 {
+            return units;
+        }
+        let mut remaining = units;
+        let mut char_col = 0;
+        if let Some(wides) = self.wide_chars.get(line) {
+            let mut prev = 0;
+            for wide in wides {
+                // The ASCII run leading up to this wide character maps 1:1.
+                let ascii = wide.char_col - prev;
+                if remaining <= ascii {
+                    break;
+                }
+                remaining -= ascii;
+                char_col += ascii;
+
+                let len = wide.len(enc);
+                if remaining < len {
+                    break;
+                }
+                remaining -= len;
+                char_col += 1;
+                prev = wide.char_col + 1;
+            }
+        }
+        char_col + remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    // Line 0 contains an accented (2-byte, 1-unit UTF-16) character; line 1
+    // starts with a non-BMP (4-byte, 2-unit UTF-16) character.
+    const SOURCE: &str = "x = \"café\"\n𝄞b";
+
+    fn index() -> LineIndex {
+        LineIndex::new(&Rope::from_str(SOURCE))
+    }
+
+    #[test]
+    fn accented_char_columns() {
+        let index = index();
+        // The closing quote sits at char column 9 on line 0.
+        let quote = 9;
+        assert_eq!(
+            index.char_to_position(quote, PositionEncoding::Utf16),
+            Position { line: 0, character: 9 }
+        );
+        assert_eq!(
+            index.char_to_position(quote, PositionEncoding::Utf8),
+            Position { line: 0, character: 10 }
+        );
+        assert_eq!(
+            index.char_to_position(quote, PositionEncoding::Utf32),
+            Position { line: 0, character: 9 }
+        );
+    }
+
+    #[test]
+    fn non_bmp_char_columns() {
+        let index = index();
+        // 'b' is the char immediately after the non-BMP symbol on line 1.
+        let b = 12;
+        assert_eq!(
+            index.char_to_position(b, PositionEncoding::Utf16),
+            Position { line: 1, character: 2 }
+        );
+        assert_eq!(
+            index.char_to_position(b, PositionEncoding::Utf8),
+            Position { line: 1, character: 4 }
+        );
+        assert_eq!(
+            index.char_to_position(b, PositionEncoding::Utf32),
+            Position { line: 1, character: 1 }
+        );
+    }
+
+    #[test]
+    fn round_trips_every_offset_and_encoding() {
+        let index = index();
+        let len = Rope::from_str(SOURCE).len_chars();
+        for enc in [
+            PositionEncoding::Utf8,
+            PositionEncoding::Utf16,
+            PositionEncoding::Utf32,
+        ] {
+            for offset in 0..=len {
+                let position = index.char_to_position(offset, enc);
+                assert_eq!(
+                    index.position_to_char(position, enc),
+                    offset,
+                    "round-trip failed for offset {offset} in {enc:?}"
+                );
+            }
+        }
+    }
+}
@@ -1,18 +1,46 @@
 mod document;
+mod line_index;
+mod semantic_tokens;
 
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use dashmap::DashMap;
 use env_logger::Env;
 use log::{debug, error, info};
-use lsp_server::{Connection, Message, ProtocolError, Request, Response};
+use lsp_server::{Connection, Message, ProtocolError, Request, RequestId, Response, ResponseError};
 use lsp_types::{
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, Hover, HoverContents, HoverParams,
-    HoverProviderCapability, MarkedString, ServerCapabilities, TextDocumentSyncCapability,
-    TextDocumentSyncKind,
+    CancelParams, CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams,
+    CompletionResponse, Diagnostic, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
+    DocumentSymbolParams, DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse,
+    Hover, HoverContents, HoverParams, HoverProviderCapability, InitializeParams, InitializeResult,
+    MarkedString, NumberOrString, OneOf, PublishDiagnosticsParams, Range, SelectionRange,
+    SelectionRangeParams, SelectionRangeProviderCapability, SemanticTokensFullOptions,
+    SemanticTokensOptions, SemanticTokensParams, SemanticTokensResult,
+    SemanticTokensServerCapabilities, ServerCapabilities, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
 };
 use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
 use document::Document;
+use line_index::PositionEncoding;
+
+/// JSON-RPC error code for a cancelled request (LSP `RequestCancelled`).
+const REQUEST_CANCELLED: i32 = -32800;
+
+/// How long the reparse worker waits for a lull before reparsing, so that a
+/// burst of `didChange` notifications only triggers a single reparse.
+const REPARSE_DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Messages sent to the background reparse worker.
+enum WorkerMessage {
+    /// The document at `uri` was edited and needs reparsing once edits settle.
+    Reparse { uri: String },
+}
 
 #[derive(Debug, Error)]
 enum LspError {
@@ -37,19 +65,47 @@ fn main() -> Result<()> {
 
     let (connection, io_threads) = Connection::stdio();
 
+    // Read the client capabilities before advertising ours so we can negotiate
+    // a position encoding the client supports.
+    let (initialize_id, initialize_params) = connection.initialize_start()?;
+    let initialize_params: InitializeParams = serde_json::from_value(initialize_params)?;
+    let encoding = PositionEncoding::negotiate(
+        initialize_params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.position_encodings.as_deref()),
+    );
+    info!("Negotiated position encoding: {:?}", encoding);
+
     let server_capabilities = ServerCapabilities {
+        position_encoding: Some(encoding.to_lsp()),
         text_document_sync: Some(TextDocumentSyncCapability::Kind(
             TextDocumentSyncKind::INCREMENTAL,
         )),
         hover_provider: Some(HoverProviderCapability::Simple(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        semantic_tokens_provider: Some(
+            SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                legend: semantic_tokens::legend(),
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                range: Some(false),
+                work_done_progress_options: Default::default(),
+            }),
+        ),
+        selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
         ..Default::default()
     };
+    let initialize_result = InitializeResult {
+        capabilities: server_capabilities,
+        server_info: None,
+    };
+    connection.initialize_finish(initialize_id, serde_json::to_value(initialize_result)?)?;
 
-    let initialization_params =
-        connection.initialize(serde_json::to_value(server_capabilities)?)?;
-
-    let backend = Backend::new();
-    main_loop(&connection, initialization_params, backend)?;
+    let backend = Arc::new(Backend::new(connection.sender.clone(), encoding));
+    main_loop(&connection, serde_json::Value::Null, backend)?;
 
     io_threads.join()?;
     info!("Shutting down server");
@@ -57,41 +113,85 @@ fn main() -> Result<()> {
 }
 
 struct Backend {
-    documents: DashMap<String, Document>,
+    documents: Arc<DashMap<String, Document>>,
+    encoding: PositionEncoding,
+    sender: Sender<Message>,
+    /// Number of diagnostics last published per URI, so stale diagnostics can
+    /// be cleared with an empty array once a document parses cleanly again.
+    diagnostic_counts: Arc<DashMap<String, usize>>,
+    /// Channel feeding the background reparse worker.
+    worker: Sender<WorkerMessage>,
+    /// Cancellation flags for in-flight requests, keyed by request id.
+    cancellations: Arc<DashMap<RequestId, Arc<AtomicBool>>>,
 }
 
 impl Backend {
-    fn new() -> Self {
+    fn new(sender: Sender<Message>, encoding: PositionEncoding) -> Self {
+        let documents = Arc::new(DashMap::new());
+        let diagnostic_counts = Arc::new(DashMap::new());
+        let (worker, worker_rx) = crossbeam_channel::unbounded();
+        spawn_reparse_worker(
+            worker_rx,
+            documents.clone(),
+            diagnostic_counts.clone(),
+            sender.clone(),
+        );
         Self {
-            documents: DashMap::new(),
+            documents,
+            encoding,
+            sender,
+            diagnostic_counts,
+            worker,
+            cancellations: Arc::new(DashMap::new()),
         }
     }
 
+    /// Returns the current version of the document, or `-1` if it is unknown.
+    fn document_version(&self, uri: &str) -> i64 {
+        self.documents.get(uri).map_or(-1, |document| document.version)
+    }
+
     fn did_open(&self, params: DidOpenTextDocumentParams) {
         let uri = params.text_document.uri;
-        let document = Document::new(uri.clone(), params.text_document.text);
+        let document = Document::new(uri.clone(), params.text_document.text, self.encoding);
         if document.symbol_table.is_empty() {
             info!("Document has no symbols");
         }
         for symbol in document.symbol_table.iter() {
             info!("Symbol: {:?}", symbol);
         }
+        publish_diagnostics(
+            &self.sender,
+            &self.diagnostic_counts,
+            &uri,
+            document.diagnostics.clone(),
+        );
         self.documents.insert(uri.to_string(), document);
         info!("Opened document: {}", uri);
     }
 
     fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(mut document) = self
-            .documents
-            .get_mut(&params.text_document.uri.to_string())
-        {
-            if let Err(e) = document.update(&params.content_changes) {
+        let uri = params.text_document.uri.clone();
+        let key = uri.to_string();
+        if let Some(mut document) = self.documents.get_mut(&key) {
+            if let Err(e) = document.apply_edit(&params.content_changes) {
                 error!("Failed to apply changes: {:?}", e);
-            } else {
-                document.line_number_map =
-                    Document::compute_line_number_map(&document.content.to_string());
-                info!("Updated document: {}", params.text_document.uri);
+                return;
             }
+        } else {
+            return;
+        }
+        // Defer the reparse to the worker so rapid edits coalesce into one.
+        if let Err(e) = self.worker.send(WorkerMessage::Reparse { uri: key }) {
+            error!("Failed to schedule reparse: {:?}", e);
+        }
+        info!("Edited document: {}", uri);
+    }
+
+    /// Sets the cancellation flag for an in-flight request, if one is tracked.
+    fn cancel(&self, id: &RequestId) {
+        if let Some(flag) = self.cancellations.get(id) {
+            flag.store(true, Ordering::SeqCst);
         }
     }
 
@@ -104,25 +204,20 @@ impl Backend {
         let position = params.text_document_position_params.position;
 
         self.documents.get(&uri).map(|document| {
-            let offset = document::to_rope_position(&document.content, position);
-            let line = document.get_line_number(offset).unwrap_or(0) as usize;
-            let character = offset - document.line_number_map[line];
+            let offset = document.offset_at(position);
+            let line = position.line as usize;
+            let character = position.character as usize;
 
+            // Report the innermost enclosing symbol: among the symbols whose
+            // span covers the cursor, pick the one with the smallest span.
             let mut symbol_info = None;
+            let mut best_len = usize::MAX;
             for symbol in document.symbol_table.iter() {
-                let start_line = symbol.location.range.start.line as usize;
-                let start_character = symbol.location.range.start.character as usize;
-                let end_line = symbol.location.range.end.line as usize;
-                let end_character = symbol.location.range.end.character as usize;
-
-                if line >= start_line
-                    && line <= end_line
-                    && (line == start_line && character >= start_character
-                        || line == end_line && character <= end_character
-                        || line > start_line && line < end_line)
-                {
+                let start = document.offset_at(symbol.location.range.start);
+                let end = document.offset_at(symbol.location.range.end);
+                if start <= offset && offset <= end && end - start < best_len {
+                    best_len = end - start;
                     symbol_info = Some(symbol);
-                    break;
                 }
             }
 
@@ -132,13 +227,16 @@ impl Backend {
                     symbol.name, symbol.kind, symbol.location
                 )
             } else {
+                // `offset_at` clamps to the document length, so guard against
+                // indexing one past the end when hovering at/after EOF.
+                let character_at = if offset < document.content.len_chars() {
+                    document.content.char(offset).to_string()
+                } else {
+                    "<eof>".to_string()
+                };
                 format!(
                     "Character: `{}`\nOffset: {}\nLine: {}\nCharacter: {}\nURI: {}",
-                    document.content.char(offset),
-                    offset,
-                    line,
-                    character,
-                    uri
+                    character_at, offset, line, character, uri
                 )
             };
 
@@ -148,9 +246,287 @@ impl Backend {
             }
         })
     }
+
+    fn document_symbols(&self, params: DocumentSymbolParams) -> DocumentSymbolResponse {
+        let uri = params.text_document.uri.to_string();
+        let symbols = self
+            .documents
+            .get(&uri)
+            .map(|document| document.symbol_table.document_symbols().to_vec())
+            .unwrap_or_default();
+        DocumentSymbolResponse::Nested(symbols)
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .to_string();
+        let position = params.text_document_position_params.position;
+
+        let document = self.documents.get(&uri)?;
+        let name = document.name_at(position)?;
+        let symbol = document.symbol_table.get(&name)?;
+        Some(GotoDefinitionResponse::Scalar(symbol.location.clone()))
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let uri = params
+            .text_document_position
+            .text_document
+            .uri
+            .to_string();
+        let position = params.text_document_position.position;
+
+        let document = self.documents.get(&uri)?;
+        let offset = document.offset_at(position);
+        let prefix = prefix_at(&document, offset);
+
+        // The enclosing scopes are the function/class symbols whose span covers
+        // the cursor; their members, plus module-level names, are in scope.
+        let enclosing: Vec<&str> = document
+            .symbol_table
+            .iter()
+            .filter(|symbol| matches!(symbol.kind, SymbolKind::FUNCTION | SymbolKind::CLASS))
+            .filter(|symbol| {
+                let start = document.offset_at(symbol.location.range.start);
+                let end = document.offset_at(symbol.location.range.end);
+                start <= offset && offset <= end
+            })
+            .map(|symbol| symbol.name.as_str())
+            .collect();
+
+        let mut items = Vec::new();
+        for symbol in document.symbol_table.iter() {
+            let visible = match symbol.container_name.as_deref() {
+                None => true,
+                Some(container) => enclosing.contains(&container),
+            };
+            if !visible {
+                continue;
+            }
+            let line = symbol.location.range.start.line + 1;
+            let character = symbol.location.range.start.character + 1;
+            items.push(CompletionItem {
+                label: symbol.name.clone(),
+                kind: Some(completion_kind(symbol.kind)),
+                detail: Some(format!("{:?} ({}:{})", symbol.kind, line, character)),
+                ..Default::default()
+            });
+        }
+
+        // Seed the list so completion is useful even in an empty file.
+        for keyword in PYTHON_KEYWORDS {
+            items.push(CompletionItem {
+                label: (*keyword).to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                ..Default::default()
+            });
+        }
+        for builtin in PYTHON_BUILTINS {
+            items.push(CompletionItem {
+                label: (*builtin).to_string(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                detail: Some("builtin".to_string()),
+                ..Default::default()
+            });
+        }
+
+        if !prefix.is_empty() {
+            items.retain(|item| item.label.starts_with(&prefix));
+        }
+
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn semantic_tokens(&self, params: SemanticTokensParams) -> Option<SemanticTokensResult> {
+        let uri = params.text_document.uri.to_string();
+        let document = self.documents.get(&uri)?;
+        Some(SemanticTokensResult::Tokens(
+            semantic_tokens::semantic_tokens(&document),
+        ))
+    }
+
+    fn selection_ranges(&self, params: SelectionRangeParams) -> Option<Vec<SelectionRange>> {
+        let uri = params.text_document.uri.to_string();
+        let document = self.documents.get(&uri)?;
+        let ranges = params
+            .positions
+            .iter()
+            .map(|position| {
+                document
+                    .selection_range(*position)
+                    .unwrap_or_else(|| SelectionRange {
+                        range: Range::default(),
+                        parent: None,
+                    })
+            })
+            .collect();
+        Some(ranges)
+    }
 }
 
-fn main_loop(connection: &Connection, _params: serde_json::Value, backend: Backend) -> Result<()> {
+/// Extracts the identifier prefix immediately preceding `offset`.
+fn prefix_at(document: &Document, offset: usize) -> String {
+    let content = &document.content;
+    let mut start = offset;
+    while start > 0 {
+        let ch = content.char(start - 1);
+        if ch.is_alphanumeric() || ch == '_' {
+            start -= 1;
+        } else {
+            break;
+        }
+    }
+    content.slice(start..offset).to_string()
+}
+
+/// Maps our `SymbolKind` onto the matching `CompletionItemKind`.
+fn completion_kind(kind: SymbolKind) -> CompletionItemKind {
+    match kind {
+        SymbolKind::FUNCTION => CompletionItemKind::FUNCTION,
+        SymbolKind::CLASS => CompletionItemKind::CLASS,
+        SymbolKind::VARIABLE => CompletionItemKind::VARIABLE,
+        SymbolKind::MODULE => CompletionItemKind::MODULE,
+        _ => CompletionItemKind::TEXT,
+    }
+}
+
+/// Python keywords offered as completions in every document.
+const PYTHON_KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+/// A selection of commonly used Python builtins offered as completions.
+const PYTHON_BUILTINS: &[&str] = &[
+    "abs", "all", "any", "bool", "dict", "enumerate", "float", "int", "len", "list", "map", "max",
+    "min", "print", "range", "set", "sorted", "str", "sum", "tuple", "type", "zip",
+];
+
+/// Pushes a `textDocument/publishDiagnostics` notification for `uri`.
+///
+/// An empty list is only sent when diagnostics were previously published, so
+/// clean documents do not generate a stream of redundant clears.
+fn publish_diagnostics(
+    sender: &Sender<Message>,
+    diagnostic_counts: &DashMap<String, usize>,
+    uri: &Url,
+    diagnostics: Vec<Diagnostic>,
+) {
+    let key = uri.to_string();
+    let previous = diagnostic_counts.get(&key).map_or(0, |count| *count);
+    if diagnostics.is_empty() && previous == 0 {
+        return;
+    }
+    diagnostic_counts.insert(key, diagnostics.len());
+
+    let params = PublishDiagnosticsParams {
+        uri: uri.clone(),
+        diagnostics,
+        version: None,
+    };
+    let notification =
+        lsp_server::Notification::new("textDocument/publishDiagnostics".to_string(), params);
+    if let Err(e) = sender.send(Message::Notification(notification)) {
+        error!("Failed to publish diagnostics: {:?}", e);
+    }
+}
+
+/// Spawns the background worker that debounces reparse requests.
+///
+/// Edits are applied to the `Rope` synchronously on the main loop; this worker
+/// only performs the expensive reparse once a burst of edits has settled,
+/// publishing fresh diagnostics afterwards.
+fn spawn_reparse_worker(
+    receiver: Receiver<WorkerMessage>,
+    documents: Arc<DashMap<String, Document>>,
+    diagnostic_counts: Arc<DashMap<String, usize>>,
+    sender: Sender<Message>,
+) {
+    thread::spawn(move || loop {
+        // Block until there is work, then coalesce any immediately following
+        // edits before reparsing.
+        let WorkerMessage::Reparse { uri } = match receiver.recv() {
+            Ok(message) => message,
+            Err(_) => break,
+        };
+
+        let mut dirty = HashSet::new();
+        dirty.insert(uri);
+        loop {
+            match receiver.recv_timeout(REPARSE_DEBOUNCE) {
+                Ok(WorkerMessage::Reparse { uri }) => {
+                    dirty.insert(uri);
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+
+        for uri in dirty {
+            let Some(mut document) = documents.get_mut(&uri) else {
+                continue;
+            };
+            document.reparse();
+            let url = document.uri.clone();
+            let diagnostics = document.diagnostics.clone();
+            drop(document);
+            publish_diagnostics(&sender, &diagnostic_counts, &url, diagnostics);
+        }
+    });
+}
+
+/// Runs a cancellable request on a worker thread.
+///
+/// The request's result is discarded — and a `RequestCancelled` error returned
+/// instead — if a `$/cancelRequest` arrives for it, or if the document it was
+/// computed against is superseded by a newer version before the reply is sent.
+fn dispatch_cancellable<F>(backend: Arc<Backend>, id: RequestId, uri: String, compute: F)
+where
+    F: FnOnce(&Backend) -> Option<serde_json::Value> + Send + 'static,
+{
+    let flag = Arc::new(AtomicBool::new(false));
+    backend.cancellations.insert(id.clone(), flag.clone());
+
+    thread::spawn(move || {
+        let version_before = backend.document_version(&uri);
+        let result = compute(&backend);
+        let superseded = backend.document_version(&uri) != version_before;
+        let cancelled = flag.load(Ordering::SeqCst);
+        backend.cancellations.remove(&id);
+
+        let response = if cancelled || superseded {
+            Response {
+                id,
+                result: None,
+                error: Some(ResponseError {
+                    code: REQUEST_CANCELLED,
+                    message: "request cancelled".to_string(),
+                    data: None,
+                }),
+            }
+        } else {
+            Response {
+                id,
+                result: Some(result.unwrap_or(serde_json::Value::Null)),
+                error: None,
+            }
+        };
+        if let Err(e) = backend.sender.send(Message::Response(response)) {
+            error!("Failed to send response: {:?}", e);
+        }
+    });
+}
+
+fn main_loop(
+    connection: &Connection,
+    _params: serde_json::Value,
+    backend: Arc<Backend>,
+) -> Result<()> {
     for msg in &connection.receiver {
         match msg {
             Message::Request(req) => {
@@ -169,15 +545,69 @@ fn main_loop(connection: &Connection, _params: serde_json::Value, backend: Backe
     Ok(())
 }
 
-fn handle_request(backend: &Backend, req: Request) -> Result<Option<Response>> {
+fn handle_request(backend: &Arc<Backend>, req: Request) -> Result<Option<Response>> {
     let id = req.id.clone();
     match req.method.as_str() {
+        // Cancellable, position-based requests are dispatched to worker threads
+        // so that a `$/cancelRequest` can drop stale work.
         "textDocument/hover" => {
             let params = from_value::<HoverParams>(req.params)?;
-            let hover = backend.hover(params);
+            let uri = params
+                .text_document_position_params
+                .text_document
+                .uri
+                .to_string();
+            dispatch_cancellable(backend.clone(), id, uri, move |b| {
+                b.hover(params).map(|h| serde_json::to_value(h).unwrap())
+            });
+            Ok(None)
+        }
+        "textDocument/definition" => {
+            let params = from_value::<GotoDefinitionParams>(req.params)?;
+            let uri = params
+                .text_document_position_params
+                .text_document
+                .uri
+                .to_string();
+            dispatch_cancellable(backend.clone(), id, uri, move |b| {
+                b.definition(params)
+                    .map(|d| serde_json::to_value(d).unwrap())
+            });
+            Ok(None)
+        }
+        "textDocument/completion" => {
+            let params = from_value::<CompletionParams>(req.params)?;
+            let uri = params.text_document_position.text_document.uri.to_string();
+            dispatch_cancellable(backend.clone(), id, uri, move |b| {
+                b.completion(params)
+                    .map(|c| serde_json::to_value(c).unwrap())
+            });
+            Ok(None)
+        }
+        "textDocument/documentSymbol" => {
+            let params = from_value::<DocumentSymbolParams>(req.params)?;
+            let symbols = backend.document_symbols(params);
             Ok(Some(Response {
                 id,
-                result: hover.map(|h| serde_json::to_value(h).unwrap()),
+                result: Some(serde_json::to_value(symbols)?),
+                error: None,
+            }))
+        }
+        "textDocument/semanticTokens/full" => {
+            let params = from_value::<SemanticTokensParams>(req.params)?;
+            let tokens = backend.semantic_tokens(params);
+            Ok(Some(Response {
+                id,
+                result: tokens.map(|t| serde_json::to_value(t).unwrap()),
+                error: None,
+            }))
+        }
+        "textDocument/selectionRange" => {
+            let params = from_value::<SelectionRangeParams>(req.params)?;
+            let ranges = backend.selection_ranges(params);
+            Ok(Some(Response {
+                id,
+                result: ranges.map(|r| serde_json::to_value(r).unwrap()),
                 error: None,
             }))
         }
@@ -185,7 +615,7 @@ fn handle_request(backend: &Backend, req: Request) -> Result<Option<Response>> {
     }
 }
 
-fn handle_notification(backend: &Backend, not: lsp_server::Notification) -> Result<()> {
+fn handle_notification(backend: &Arc<Backend>, not: lsp_server::Notification) -> Result<()> {
     match not.method.as_str() {
         "textDocument/didOpen" => {
             let params = from_value::<DidOpenTextDocumentParams>(not.params)?;
@@ -195,6 +625,14 @@ fn handle_notification(backend: &Backend, not: lsp_server::Notification) -> Resu
             let params = from_value::<DidChangeTextDocumentParams>(not.params)?;
             backend.did_change(params);
         }
+        "$/cancelRequest" => {
+            let params = from_value::<CancelParams>(not.params)?;
+            let id = match params.id {
+                NumberOrString::Number(number) => RequestId::from(number),
+                NumberOrString::String(string) => RequestId::from(string),
+            };
+            backend.cancel(&id);
+        }
         _ => {}
     }
     Ok(())
@@ -0,0 +1,210 @@
+//! Semantic token generation from the ruff AST.
+//!
+//! The parsed module is walked once, every classified span is collected and
+//! sorted by start position, and the result is delta-encoded into the LSP
+//! semantic-token stream in the document's negotiated position encoding.
+
+use crate::document::Document;
+use lsp_types::{SemanticToken, SemanticTokenType, SemanticTokens, SemanticTokensLegend};
+use ruff_python_ast::visitor::{self, Visitor};
+use ruff_python_ast::{Expr, Parameters, Ranged, Stmt};
+use ruff_text_size::{TextRange, TextSize};
+
+// Token type indices. These MUST stay in sync with the order of [`legend`].
+const NAMESPACE: u32 = 0;
+const FUNCTION: u32 = 1;
+const CLASS: u32 = 2;
+const VARIABLE: u32 = 3;
+const PARAMETER: u32 = 4;
+const KEYWORD: u32 = 5;
+const STRING: u32 = 6;
+const NUMBER: u32 = 7;
+
+/// The legend advertised to the client, describing the token type indices.
+pub fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: vec![
+            SemanticTokenType::NAMESPACE,
+            SemanticTokenType::FUNCTION,
+            SemanticTokenType::CLASS,
+            SemanticTokenType::VARIABLE,
+            SemanticTokenType::PARAMETER,
+            SemanticTokenType::KEYWORD,
+            SemanticTokenType::STRING,
+            SemanticTokenType::NUMBER,
+        ],
+        token_modifiers: vec![],
+    }
+}
+
+/// Computes the semantic tokens for a document from its current AST.
+pub fn semantic_tokens(document: &Document) -> SemanticTokens {
+    let source = document.content.to_string();
+    let mut collector = TokenCollector {
+        source: &source,
+        tokens: Vec::new(),
+    };
+    if let Some(ast) = &document.ast {
+        for stmt in &ast.body {
+            collector.visit_stmt(stmt);
+        }
+    }
+
+    let mut tokens = collector.tokens;
+    tokens.sort_by_key(|(range, _)| range.start());
+
+    let mut builder = SemanticTokensBuilder::default();
+    for (range, token_type) in tokens {
+        let lsp_range = document.range_of(&range);
+        // Each token in the LSP stream lies on a single line, so skip multi-line spans.
+        if lsp_range.start.line != lsp_range.end.line {
+            continue;
+        }
+        let length = lsp_range.end.character.saturating_sub(lsp_range.start.character);
+        if length == 0 {
+            continue;
+        }
+        builder.push(lsp_range.start.line, lsp_range.start.character, length, token_type);
+    }
+
+    SemanticTokens {
+        result_id: None,
+        data: builder.data,
+    }
+}
+
+/// Delta-encodes absolute `(line, start, length, type)` tokens into the LSP wire
+/// representation. Tokens must be pushed in ascending source order.
+#[derive(Default)]
+struct SemanticTokensBuilder {
+    prev_line: u32,
+    prev_start: u32,
+    data: Vec<SemanticToken>,
+}
+
+impl SemanticTokensBuilder {
+    fn push(&mut self, line: u32, start: u32, length: u32, token_type: u32) {
+        let delta_line = line - self.prev_line;
+        let delta_start = if delta_line == 0 {
+            start - self.prev_start
+        } else {
+            start
+        };
+        self.data.push(SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type,
+            token_modifiers_bitset: 0,
+        });
+        self.prev_line = line;
+        self.prev_start = start;
+    }
+}
+
+/// Collects classified byte spans while walking the AST.
+struct TokenCollector<'s> {
+    source: &'s str,
+    tokens: Vec<(TextRange, u32)>,
+}
+
+impl TokenCollector<'_> {
+    fn push(&mut self, range: TextRange, token_type: u32) {
+        self.tokens.push((range, token_type));
+    }
+
+    /// Emits a keyword token for the leading `keyword` word of a statement whose
+    /// range starts at the keyword (e.g. `import`/`from`).
+    fn push_keyword(&mut self, range: TextRange, keyword: &str) {
+        let start = range.start();
+        let end = start + TextSize::from(keyword.len() as u32);
+        self.tokens.push((TextRange::new(start, end), KEYWORD));
+    }
+
+    /// Emits the `def`/`class` keyword token for a definition.
+    ///
+    /// The node range starts at the decorator list (and at `async` for async
+    /// defs), so the keyword position is found by skipping past any decorators
+    /// and the optional `async` marker in the source text.
+    fn push_def_keyword(
+        &mut self,
+        node_start: TextSize,
+        decorator_end: Option<TextSize>,
+        is_async: bool,
+        keyword: &str,
+    ) {
+        let mut pos = decorator_end.unwrap_or(node_start).to_usize();
+        pos = skip_whitespace(self.source, pos);
+        if is_async {
+            pos = skip_whitespace(self.source, pos + "async".len());
+        }
+        let start = TextSize::from(pos as u32);
+        let end = start + TextSize::from(keyword.len() as u32);
+        self.tokens.push((TextRange::new(start, end), KEYWORD));
+    }
+
+    fn collect_parameters(&mut self, parameters: &Parameters) {
+        for param in parameters
+            .posonlyargs
+            .iter()
+            .chain(parameters.args.iter())
+            .chain(parameters.kwonlyargs.iter())
+        {
+            self.push(param.parameter.name.range(), PARAMETER);
+        }
+        if let Some(vararg) = &parameters.vararg {
+            self.push(vararg.name.range(), PARAMETER);
+        }
+        if let Some(kwarg) = &parameters.kwarg {
+            self.push(kwarg.name.range(), PARAMETER);
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for TokenCollector<'_> {
+    fn visit_stmt(&mut self, stmt: &'a Stmt) {
+        match stmt {
+            Stmt::FunctionDef(def) => {
+                let decorator_end = def.decorator_list.last().map(|d| d.range().end());
+                self.push_def_keyword(def.range().start(), decorator_end, def.is_async, "def");
+                self.push(def.name.range(), FUNCTION);
+                self.collect_parameters(&def.parameters);
+            }
+            Stmt::ClassDef(def) => {
+                let decorator_end = def.decorator_list.last().map(|d| d.range().end());
+                self.push_def_keyword(def.range().start(), decorator_end, false, "class");
+                self.push(def.name.range(), CLASS);
+            }
+            Stmt::Import(import) => self.push_keyword(import.range(), "import"),
+            Stmt::ImportFrom(import) => {
+                self.push_keyword(import.range(), "from");
+                if let Some(module) = &import.module {
+                    self.push(module.range(), NAMESPACE);
+                }
+            }
+            _ => {}
+        }
+        visitor::walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &'a Expr) {
+        match expr {
+            Expr::Name(name) => self.push(name.range(), VARIABLE),
+            Expr::StringLiteral(string) => self.push(string.range(), STRING),
+            Expr::NumberLiteral(number) => self.push(number.range(), NUMBER),
+            _ => {}
+        }
+        visitor::walk_expr(self, expr);
+    }
+}
+
+/// Advances past any ASCII whitespace in `source` starting at byte `pos`,
+/// returning the offset of the first non-whitespace byte (or the end).
+fn skip_whitespace(source: &str, pos: usize) -> usize {
+    let bytes = source.as_bytes();
+    let mut pos = pos;
+    while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+        pos += 1;
+    }
+    pos
+}